@@ -0,0 +1,732 @@
+//! Core CSV-to-histogram pipeline for `fast_hdr`, usable as a library
+//! independent of the CLI. The `cli` feature pulls in `clap` so the binary
+//! can derive `Args` directly off these types; embedders depending on this
+//! crate without that feature get a minimal surface: `new_reader` to build
+//! a `Measurement` stream and `HistogramBuilder` to turn one (or a joined
+//! pair) into a `Histogram<u64>`.
+
+#[cfg(feature = "cli")]
+use clap::clap_derive::ArgEnum;
+use csv::ByteRecord;
+use flate2::read::GzDecoder;
+use hdrhistogram::Histogram;
+use thiserror::Error;
+use std::{io::{self, Seek, SeekFrom}, num::ParseIntError, collections::{HashMap, HashSet}, fs::File};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("io error {0}")]
+    IO(io::Error),
+
+    #[error("histogram parameter error {0}")]
+    HistError(hdrhistogram::CreationError),
+
+    #[error("error reading CSV {0}")]
+    Csv(csv::Error),
+
+    #[error("invalid input: {0}")]
+    UserError(String),
+
+    #[error("illegal value in file")]
+    ParseIntError(ParseIntError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<hdrhistogram::CreationError> for Error {
+    fn from(err: hdrhistogram::CreationError) -> Self {
+        Error::HistError(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Error::Csv(err)
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(err: ParseIntError) -> Self {
+        Error::ParseIntError(err)
+    }
+}
+
+impl From<String> for Error {
+    fn from(str: String) -> Self {
+        Error::UserError(str)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ArgEnum))]
+pub enum OOBRule {
+    Error,
+    Drop,
+    Saturate
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ArgEnum))]
+pub enum TrimMode {
+    None,
+    Headers,
+    Fields,
+    All,
+}
+
+impl From<TrimMode> for csv::Trim {
+    fn from(mode: TrimMode) -> Self {
+        match mode {
+            TrimMode::None => csv::Trim::None,
+            TrimMode::Headers => csv::Trim::Headers,
+            TrimMode::Fields => csv::Trim::Fields,
+            TrimMode::All => csv::Trim::All,
+        }
+    }
+}
+
+/// CSV dialect knobs threaded into the underlying `csv::ReaderBuilder`.
+#[derive(Clone)]
+pub struct Dialect {
+    pub delimiter: u8,
+    pub trim: csv::Trim,
+    pub flexible: bool,
+    pub quote: u8,
+    pub quoting: bool,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: b',',
+            trim: csv::Trim::None,
+            flexible: false,
+            quote: b'"',
+            quoting: true,
+        }
+    }
+}
+
+// A small splitmix64 PRNG, good enough for reservoir sampling and avoids
+// pulling in a dependency just to pick random slots.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform integer in [0, bound).
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+fn reader_builder(dialect: &Dialect) -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .delimiter(dialect.delimiter)
+        .trim(dialect.trim)
+        .flexible(dialect.flexible)
+        .quote(dialect.quote)
+        .quoting(dialect.quoting);
+    builder
+}
+
+pub struct Measurement {
+    primary: u64,
+    rhs: Option<u64>,
+    join: Option<String>,
+}
+
+struct Reader<T>{
+    reader: csv::Reader<T>,
+    record: ByteRecord,
+    primary_idx: usize,
+    rhs_idx: Option<usize>,
+    join_idx: Option<usize>,
+}
+
+fn get_bytes(record: &ByteRecord, idx: usize) -> Result<&[u8]> {
+    record.get(idx).ok_or_else(|| {
+        Error::UserError(format!("{} is not a valid index in {:?}", idx, record))
+    })
+}
+
+fn get_str(record: &ByteRecord, idx: usize) -> Result<&str> {
+    let bytes = get_bytes(record, idx)?;
+    std::str::from_utf8(bytes).map_err(|_| {
+        Error::UserError(format!("field {} is not valid UTF-8 in {:?}", idx, record))
+    })
+}
+
+// Parses a u64 directly out of the raw field bytes, avoiding the UTF-8
+// validation and heap allocation a `str::parse` on a materialized String
+// would require on every row.
+fn get(record: &ByteRecord, idx: usize) -> Result<u64> {
+    let bytes = get_bytes(record, idx)?;
+    if bytes.is_empty() {
+        return Result::Err(Error::UserError(format!("field {} is empty in {:?}", idx, record)));
+    }
+    let mut acc: u64 = 0;
+    for &byte in bytes {
+        if !byte.is_ascii_digit() {
+            return Result::Err(Error::UserError(format!("field {} is not an integer in {:?}", idx, record)));
+        }
+        acc = acc.checked_mul(10)
+            .and_then(|acc| acc.checked_add((byte - b'0') as u64))
+            .ok_or_else(|| Error::UserError(format!("field {} overflows u64 in {:?}", idx, record)))?;
+    }
+    Result::Ok(acc)
+}
+
+pub type MeasurementIterator = dyn Iterator<Item = Result<Measurement>>;
+
+
+pub fn new_reader(fname: &str, primary_cname: &str, rhs_cname: Option<&str>, join_cname: Option<&str>, dialect: &Dialect) -> Result<Box<MeasurementIterator>> {
+    let result: Result<Box<dyn Iterator<Item = Result<Measurement>>>> = if fname.ends_with(".gz") {
+        new_compressed_reader(fname, primary_cname, rhs_cname, join_cname, dialect).map(|r| { Box::new(r) as Box<MeasurementIterator> })
+    } else {
+        new_uncompressed_reader(fname, primary_cname, rhs_cname, join_cname, dialect).map(|r| { Box::new(r) as Box<MeasurementIterator> })
+    };
+    result
+}
+
+fn new_uncompressed_reader(fname: &str, primary_cname: &str, rhs_cname: Option<&str>, join_cname: Option<&str>, dialect: &Dialect) -> Result<Reader<File>> {
+    let mut csv = reader_builder(dialect).from_path(fname)?;
+    let header = csv.headers()?;
+    let primary_idx = header.iter().position(|name| { name == primary_cname});
+    let primary_idx = match primary_idx {
+        Option::None => {
+            return Result::Err(Error::UserError(format!("{} is not a column in {}", primary_cname, fname)));
+        },
+        Option::Some(value) => value,
+    };
+    let rhs_idx = rhs_cname.and_then(|rhs_cname| {
+        header.iter().position(|name| { name == rhs_cname})
+    });
+    let join_idx = join_cname.and_then(|join_cname| {
+        header.iter().position(|name| { name == join_cname })
+    });
+
+    Result::Ok(Reader {
+        reader: csv,
+        record: ByteRecord::new(),
+        primary_idx,
+        rhs_idx,
+        join_idx,
+    })
+}
+
+fn new_compressed_reader(fname: &str, primary_cname: &str, rhs_cname: Option<&str>, join_cname: Option<&str>, dialect: &Dialect) -> Result<Reader<GzDecoder<File>>> {
+    let file = File::open(fname)?;
+    let file = GzDecoder::new(file);
+    let mut csv = reader_builder(dialect).from_reader(file);
+    let header = csv.headers()?;
+    let primary_idx = header.iter().position(|name| { name == primary_cname});
+    let primary_idx = match primary_idx {
+        Option::None => {
+            return Result::Err(Error::UserError(format!("{} is not a column in {}", primary_cname, fname)));
+        },
+        Option::Some(value) => value,
+    };
+    let rhs_idx = rhs_cname.and_then(|rhs_cname| {
+        header.iter().position(|name| { name == rhs_cname})
+    });
+    let join_idx = join_cname.and_then(|join_cname| {
+        header.iter().position(|name| { name == join_cname })
+    });
+
+    Result::Ok(Reader {
+        reader: csv,
+        record: ByteRecord::new(),
+        primary_idx,
+        rhs_idx,
+        join_idx,
+    })
+}
+
+
+impl <T: io::Read> Iterator for Reader<T> {
+    type Item = Result<Measurement>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_byte_record(&mut self.record) {
+            Err(err) => {
+                Option::Some(Result::Err(err.into()))
+            }
+            Ok(false) => Option::None,
+            Ok(true) => {
+                let value = &self.record;
+                let lhs = get(value, self.primary_idx);
+                let rhs = self.rhs_idx.map(|rhs_idx| {
+                    get(value, rhs_idx)
+                });
+                let join = self.join_idx.map(|join_idx| {
+                    get_str(value, join_idx)
+                });
+                // abort on required column read error
+                let rhs = match rhs {
+                    Option::None => Option::None,
+                    Option::Some(Result::Ok(v)) => Option::Some(v),
+                    Option::Some(Result::Err(err)) => {
+                        return Option::Some(Result::Err(err));
+                    }
+                };
+                let join = match join {
+                    Option::None => Option::None,
+                    Option::Some(Result::Ok(v)) => Option::Some(v),
+                    Option::Some(Result::Err(err)) => {
+                        return Option::Some(Result::Err(err));
+                    }
+                };
+
+                Option::Some(lhs.map(|lhs| {
+                    Measurement{
+                        primary: lhs,
+                        rhs,
+                        join: join.map(|join| { join.to_string() }),
+                    }
+                }))
+            }
+        }
+    }
+}
+
+
+
+struct JoinRHS {
+    reader: Box<MeasurementIterator>,
+    ooo: HashMap<String, Measurement>,
+    // Keys matched directly (without ever passing through `ooo`) still need
+    // to be remembered, or a later duplicate of that key would sail through
+    // with nothing to collide against.
+    seen: HashSet<String>,
+}
+
+impl JoinRHS {
+    fn new(reader: Box<MeasurementIterator>) -> JoinRHS {
+        JoinRHS { reader, ooo: HashMap::new(), seen: HashSet::new() }
+    }
+
+    fn take(&mut self, join_key: String) -> Result<Option<Measurement>> {
+        if let Some(record) = self.ooo.remove(&join_key) {
+            Result::Ok(Option::Some(record))
+        } else {
+            for record in self.reader.by_ref() {
+                let record = record?;
+                let key = record.join.clone().unwrap();
+                if !self.seen.insert(key.clone()) {
+                    return Result::Err(Error::UserError(format!("duplicate join key {}", key)));
+                }
+                if key == join_key {
+                    return Result::Ok(Option::Some(record))
+                } else {
+                    self.ooo.insert(key, record);
+                }
+            }
+            Result::Ok(Option::None)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ArgEnum))]
+pub enum JoinMode {
+    /// Buffer out-of-order RHS rows into a HashMap as the stream is scanned (the original behavior)
+    Buffer,
+    /// Index RHS join keys to byte offsets in a first pass, then seek for each match
+    Index,
+}
+
+// Indexes a seekable RHS file by join key -> byte offset of its record, so a
+// second pass can seek directly to a match instead of buffering every
+// out-of-order row in memory. Only the offset (a u64) is kept per key, not
+// the parsed Measurement.
+struct IndexedJoin {
+    reader: csv::Reader<File>,
+    record: ByteRecord,
+    primary_idx: usize,
+    offsets: HashMap<String, u64>,
+}
+
+impl IndexedJoin {
+    fn new(fname: &str, primary_cname: &str, join_cname: &str, dialect: &Dialect) -> Result<IndexedJoin> {
+        let mut reader = reader_builder(dialect).from_path(fname)?;
+        let header = reader.headers()?;
+        let primary_idx = header.iter().position(|name| { name == primary_cname })
+            .ok_or_else(|| Error::UserError(format!("{} is not a column in {}", primary_cname, fname)))?;
+        let join_idx = header.iter().position(|name| { name == join_cname })
+            .ok_or_else(|| Error::UserError(format!("{} is not a column in {}", join_cname, fname)))?;
+
+        let mut offsets: HashMap<String, u64> = HashMap::new();
+        let mut record = ByteRecord::new();
+        loop {
+            let pos = reader.position().byte();
+            if !reader.read_byte_record(&mut record)? {
+                break;
+            }
+            let key = get_str(&record, join_idx)?.to_string();
+            if offsets.insert(key.clone(), pos).is_some() {
+                return Result::Err(Error::UserError(format!("duplicate join key {} in {}", key, fname)));
+            }
+        }
+
+        Result::Ok(IndexedJoin {
+            reader,
+            record: ByteRecord::new(),
+            primary_idx,
+            offsets,
+        })
+    }
+
+    fn take(&mut self, join_key: &str) -> Result<Option<u64>> {
+        let offset = match self.offsets.remove(join_key) {
+            Option::Some(offset) => offset,
+            Option::None => return Result::Ok(Option::None),
+        };
+
+        let mut pos = csv::Position::new();
+        pos.set_byte(offset);
+        self.reader.seek(pos)?;
+
+        if self.reader.read_byte_record(&mut self.record)? {
+            Result::Ok(Option::Some(get(&self.record, self.primary_idx)?))
+        } else {
+            Result::Ok(Option::None)
+        }
+    }
+}
+
+enum RhsJoinInner {
+    Buffered(JoinRHS),
+    Seeked(IndexedJoin),
+    Materialized(HashMap<String, u64>),
+}
+
+/// A RHS join strategy selected by `JoinMode`, built once and then queried
+/// per LHS row via `take`. Opaque so callers don't need to know which
+/// strategy backs a given instance.
+pub struct RhsJoin {
+    inner: RhsJoinInner,
+}
+
+impl RhsJoin {
+    pub fn new(mode: JoinMode, fname: &str, rhs_cname: &str, join_cname: &str, dialect: &Dialect) -> Result<RhsJoin> {
+        let inner = match mode {
+            JoinMode::Buffer => {
+                let reader = new_reader(fname, rhs_cname, Option::None, Option::Some(join_cname), dialect)?;
+                RhsJoinInner::Buffered(JoinRHS::new(reader))
+            }
+            JoinMode::Index if fname.ends_with(".gz") => {
+                // gzip streams can't be seeked into, so fall back to a
+                // first pass that materializes key -> rhs value directly
+                // (cheaper than buffering the full out-of-order Measurement).
+                let reader = new_reader(fname, rhs_cname, Option::None, Option::Some(join_cname), dialect)?;
+                let mut map: HashMap<String, u64> = HashMap::new();
+                for record in reader {
+                    let record = record?;
+                    let key = record.join.ok_or_else(|| {
+                        Error::UserError(format!("{} is not a column in {}", join_cname, fname))
+                    })?;
+                    if map.insert(key.clone(), record.primary).is_some() {
+                        return Result::Err(Error::UserError(format!("duplicate join key {} in {}", key, fname)));
+                    }
+                }
+                RhsJoinInner::Materialized(map)
+            }
+            JoinMode::Index => {
+                RhsJoinInner::Seeked(IndexedJoin::new(fname, rhs_cname, join_cname, dialect)?)
+            }
+        };
+
+        Result::Ok(RhsJoin { inner })
+    }
+
+    fn take(&mut self, join_key: String) -> Result<Option<u64>> {
+        match &mut self.inner {
+            RhsJoinInner::Buffered(j) => Result::Ok(j.take(join_key)?.map(|record| record.primary)),
+            RhsJoinInner::Seeked(idx) => idx.take(&join_key),
+            RhsJoinInner::Materialized(map) => Result::Ok(map.remove(&join_key)),
+        }
+    }
+}
+
+/// Builds a `Histogram<u64>` from one or two `Measurement` streams,
+/// applying the configured `OOBRule` to out-of-bounds diffs. This holds the
+/// diff/OOB logic that used to be duplicated between the single-file and
+/// dual-file (joined) code paths.
+#[derive(Clone)]
+pub struct HistogramBuilder {
+    max_value: u64,
+    sigfigs: u8,
+    oob: OOBRule,
+    max_records: Option<u64>,
+    sample: Option<(usize, u64)>,
+}
+
+impl HistogramBuilder {
+    pub fn new(max_value: u64, sigfigs: u8) -> HistogramBuilder {
+        HistogramBuilder { max_value, sigfigs, oob: OOBRule::Error, max_records: Option::None, sample: Option::None }
+    }
+
+    pub fn oob(mut self, oob: OOBRule) -> HistogramBuilder {
+        self.oob = oob;
+        self
+    }
+
+    /// Stops after `max_records` diffs have been taken from the stream
+    /// (diffs discarded by `OOBRule::Drop` count toward the cap). If
+    /// `sample` is also set, this instead caps how many diffs the reservoir
+    /// sampler draws from before sampling.
+    pub fn max_records(mut self, max_records: Option<u64>) -> HistogramBuilder {
+        self.max_records = max_records;
+        self
+    }
+
+    /// Retains at most `size` diffs via Algorithm R reservoir sampling,
+    /// seeded by `seed` for reproducibility, instead of recording every one.
+    pub fn sample(mut self, size: Option<usize>, seed: u64) -> HistogramBuilder {
+        self.sample = size.map(|size| (size, seed));
+        self
+    }
+
+    /// Records `lhs - rhs` for each measurement into a fresh histogram.
+    ///
+    /// When `rhs` is `None`, each `lhs` record must already carry its rhs
+    /// value (the same-row diff used for single-file mode). When `rhs` is
+    /// `Some`, records are paired up via `Measurement::join` against the
+    /// given join strategy (buffered or indexed, per `JoinMode`).
+    pub fn record_diff(&self, lhs: Box<MeasurementIterator>, rhs: Option<RhsJoin>) -> Result<Histogram<u64>> {
+        let mut hist: Histogram<u64> = Histogram::new_with_max(self.max_value, self.sigfigs)?;
+        let pairs = self.diff_pairs(lhs, rhs);
+
+        match self.sample {
+            Option::Some((size, seed)) => {
+                let pairs: Box<dyn Iterator<Item = Result<(u64, u64)>>> = match self.max_records {
+                    Option::Some(max_records) => Box::new(pairs.take(max_records as usize)),
+                    Option::None => pairs,
+                };
+                for (lhs, rhs) in self.reservoir_sample(pairs, size, seed)? {
+                    self.record_one(&mut hist, lhs, rhs)?;
+                }
+            }
+            Option::None => {
+                let pairs: Box<dyn Iterator<Item = Result<(u64, u64)>>> = match self.max_records {
+                    Option::Some(max_records) => Box::new(pairs.take(max_records as usize)),
+                    Option::None => pairs,
+                };
+                for pair in pairs {
+                    let (lhs, rhs) = pair?;
+                    self.record_one(&mut hist, lhs, rhs)?;
+                }
+            }
+        }
+
+        Result::Ok(hist)
+    }
+
+    // Flattens either diff source into a single stream of (lhs, rhs) pairs
+    // so record limiting and sampling only need to be implemented once.
+    fn diff_pairs(&self, lhs: Box<MeasurementIterator>, rhs: Option<RhsJoin>) -> Box<dyn Iterator<Item = Result<(u64, u64)>>> {
+        match rhs {
+            Option::None => {
+                Box::new(lhs.filter_map(|record| {
+                    match record {
+                        Result::Err(err) => Option::Some(Result::Err(err)),
+                        Result::Ok(record) => record.rhs.map(|rhs| Result::Ok((record.primary, rhs))),
+                    }
+                }))
+            }
+            Option::Some(mut rhs_join) => {
+                Box::new(lhs.filter_map(move |record| {
+                    let record = match record {
+                        Result::Err(err) => return Option::Some(Result::Err(err)),
+                        Result::Ok(record) => record,
+                    };
+                    let join_value = record.join?;
+                    match rhs_join.take(join_value) {
+                        Result::Err(err) => Option::Some(Result::Err(err)),
+                        Result::Ok(Option::None) => Option::None,
+                        Result::Ok(Option::Some(rhs_value)) => Option::Some(Result::Ok((record.primary, rhs_value))),
+                    }
+                }))
+            }
+        }
+    }
+
+    // Algorithm R: keeps the first `size` pairs, then for the i-th
+    // subsequent pair picks a random slot in [0, i]; a hit replaces that
+    // slot. Yields a statistically representative sample without requiring
+    // the caller to know the stream length up front.
+    fn reservoir_sample(&self, pairs: Box<dyn Iterator<Item = Result<(u64, u64)>>>, size: usize, seed: u64) -> Result<Vec<(u64, u64)>> {
+        let mut reservoir: Vec<(u64, u64)> = Vec::with_capacity(size);
+        let mut rng = Rng::new(seed);
+
+        for (i, pair) in pairs.enumerate() {
+            let pair = pair?;
+            if i < size {
+                reservoir.push(pair);
+            } else {
+                let j = rng.below((i + 1) as u64) as usize;
+                if j < size {
+                    reservoir[j] = pair;
+                }
+            }
+        }
+
+        Result::Ok(reservoir)
+    }
+
+    // Baseline single-file mode let Error/Saturate through unconditionally
+    // for a negative diff (erroring or saturating on it), while baseline
+    // join mode skipped it silently under both rules. Recording used to be
+    // duplicated per mode, so each inherited its own answer; unifying them
+    // here means picking one. We keep the single-file behavior: Error/Saturate
+    // see every diff including negative ones, and only Drop range-checks.
+    fn record_one(&self, hist: &mut Histogram<u64>, lhs: u64, rhs: u64) -> Result<()> {
+        let lhs = lhs as i64;
+        let rhs = rhs as i64;
+        let v = lhs - rhs;
+
+        match self.oob {
+            OOBRule::Error => {
+                hist.record(v as u64).map_err(|err| {Error::UserError(format!("could not record {}", err))})?;
+            },
+
+            OOBRule::Saturate => {
+                hist.saturating_record(v as u64)
+            },
+
+            OOBRule::Drop => {
+                if v >= 0 && v < self.max_value as i64 {
+                    hist.record(v as u64).map_err(|err| {Error::UserError(format!("could not record {}", err))})?;
+                }
+            }
+        }
+
+        Result::Ok(())
+    }
+
+    // Records every same-row lhs/rhs diff found in the byte range [start, end)
+    // of `fname`, which must already be aligned to line boundaries.
+    fn record_range(&self, fname: &str, primary_idx: usize, rhs_idx: usize, dialect: &Dialect, start: u64, end: u64) -> Result<Histogram<u64>> {
+        let mut hist: Histogram<u64> = Histogram::new_with_max(self.max_value, self.sigfigs)?;
+        if start >= end {
+            return Result::Ok(hist);
+        }
+
+        let mut file = File::open(fname)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = reader_builder(dialect).has_headers(false).from_reader(file);
+        let mut record = ByteRecord::new();
+        let limit = end - start;
+
+        loop {
+            if reader.position().byte() >= limit {
+                break;
+            }
+            if !reader.read_byte_record(&mut record)? {
+                break;
+            }
+            let lhs = get(&record, primary_idx)?;
+            let rhs = get(&record, rhs_idx)?;
+            self.record_one(&mut hist, lhs, rhs)?;
+        }
+
+        Result::Ok(hist)
+    }
+}
+
+// Picks `threads - 1` interior split points by walking the CSV records
+// themselves (rather than scanning for raw `\n` bytes), so a boundary never
+// lands inside a quoted field that happens to contain an embedded newline.
+// Targets are evenly spaced over [header_end, total_len) and each is nudged
+// forward to the byte offset just past the record that crosses it.
+fn compute_boundaries(fname: &str, dialect: &Dialect, header_end: u64, total_len: u64, threads: usize) -> Result<Vec<u64>> {
+    let mut boundaries = vec![header_end];
+
+    let mut file = File::open(fname)?;
+    file.seek(SeekFrom::Start(header_end))?;
+    let mut reader = reader_builder(dialect).has_headers(false).from_reader(file);
+    let mut record = ByteRecord::new();
+
+    for i in 1..threads {
+        let target = header_end + (total_len - header_end) * i as u64 / threads as u64;
+        loop {
+            if header_end + reader.position().byte() >= target {
+                boundaries.push(header_end + reader.position().byte());
+                break;
+            }
+            if !reader.read_byte_record(&mut record)? {
+                boundaries.push(total_len);
+                break;
+            }
+        }
+    }
+
+    boundaries.push(total_len);
+    boundaries.dedup();
+    Result::Ok(boundaries)
+}
+
+/// Builds a histogram from the same-row `lhs_column`/`rhs_column` diff in
+/// `fname`, splitting the file across `threads` worker threads and merging
+/// their thread-local histograms. Falls back to the single-threaded path
+/// for `threads <= 1` or `.gz` inputs, since gzip streams can't be
+/// range-split.
+pub fn record_diff_threaded(fname: &str, lhs_column: &str, rhs_column: &str, dialect: &Dialect, builder: &HistogramBuilder, threads: usize) -> Result<Histogram<u64>> {
+    if threads <= 1 || fname.ends_with(".gz") {
+        let lhs = new_reader(fname, lhs_column, Option::Some(rhs_column), Option::None, dialect)?;
+        return builder.record_diff(lhs, Option::None);
+    }
+
+    let mut header_reader = reader_builder(dialect).from_path(fname)?;
+    let header = header_reader.headers()?;
+    let primary_idx = header.iter().position(|name| { name == lhs_column })
+        .ok_or_else(|| Error::UserError(format!("{} is not a column in {}", lhs_column, fname)))?;
+    let rhs_idx = header.iter().position(|name| { name == rhs_column })
+        .ok_or_else(|| Error::UserError(format!("{} is not a column in {}", rhs_column, fname)))?;
+    let header_end = header_reader.position().byte();
+    drop(header_reader);
+
+    let total_len = std::fs::metadata(fname)?.len();
+    let boundaries = compute_boundaries(fname, dialect, header_end, total_len, threads)?;
+
+    let mut handles = Vec::new();
+    for window in boundaries.windows(2) {
+        let start = window[0];
+        let end = window[1];
+        let fname = fname.to_string();
+        let dialect = dialect.clone();
+        let builder = builder.clone();
+        handles.push(std::thread::spawn(move || {
+            builder.record_range(&fname, primary_idx, rhs_idx, &dialect, start, end)
+        }));
+    }
+
+    let mut hist: Histogram<u64> = Histogram::new_with_max(builder.max_value, builder.sigfigs)?;
+    for handle in handles {
+        let worker_hist = handle.join()
+            .map_err(|_| Error::UserError("histogram worker thread panicked".into()))??;
+        hist.add(worker_hist).map_err(|err| {
+            Error::UserError(format!("could not merge worker histogram: {}", err))
+        })?;
+    }
+
+    Result::Ok(hist)
+}